@@ -6,38 +6,107 @@ use {
     solana_streamer::streamer::StakedNodes,
     std::{
         collections::HashMap,
+        fs,
         net::IpAddr,
+        path::{Path, PathBuf},
         sync::{
             atomic::{AtomicBool, Ordering},
             Arc, RwLock, RwLockReadGuard,
         },
         thread::{self, sleep, Builder, JoinHandle},
-        time::{Duration, Instant},
+        time::{Duration, Instant, SystemTime},
     },
 };
 
 const IP_TO_STAKE_REFRESH_DURATION: Duration = Duration::from_secs(5);
 
 pub struct StakedNodesUpdaterService {
-    thread_hdl: JoinHandle<()>,
+    thread_hdl: Option<JoinHandle<()>>,
 }
 
 #[derive(Default, Deserialize, Clone)]
 pub struct StakedNodesOverrides {
     #[serde(deserialize_with = "deserialize_pubkey_map")]
-    pub staked_map_id: HashMap<Pubkey, u64>,
+    pub staked_map_id: HashMap<Pubkey, StakeOverride>,
 }
 
-pub fn deserialize_pubkey_map<'de, D>(des: D) -> Result<HashMap<Pubkey, u64>, D::Error>
+/// A single entry of `StakedNodesOverrides`, expressed either as an absolute
+/// stake or relative to the cluster's total stake / the node's own existing
+/// stake. Resolved to an absolute stake in `try_refresh_stake_maps`, once
+/// `total_stake` for the cycle is known.
+#[derive(Debug, Clone, Copy)]
+pub enum StakeOverride {
+    /// An absolute stake, in lamports.
+    Abs(u64),
+    /// Basis points (1/100th of a percent) of the cluster's total stake.
+    Bps(u64),
+    /// A multiplier of the node's own existing stake.
+    Mul(f64),
+}
+
+impl StakeOverride {
+    fn resolve(&self, existing_stake: u64, total_stake: u64) -> u64 {
+        match self {
+            StakeOverride::Abs(stake) => *stake,
+            StakeOverride::Bps(bps) => {
+                ((total_stake as u128) * (*bps as u128) / 10_000) as u64
+            }
+            StakeOverride::Mul(mul) => {
+                // Scale `mul` to a basis-point integer once and do the actual
+                // multiply in u128 space, so large stakes (above ~2^53
+                // lamports) don't lose precision round-tripping through f64.
+                let mul_bps = (*mul * 10_000.0).round() as u128;
+                ((existing_stake as u128) * mul_bps / 10_000) as u64
+            }
+        }
+    }
+}
+
+/// Tagged, on-the-wire representation of `StakeOverride`: `{"bps": N}`,
+/// `{"mul": f}`, or `{"abs": N}`.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TaggedStakeOverride {
+    Abs(u64),
+    Bps(u64),
+    Mul(f64),
+}
+
+/// Either the legacy bare `u64` (resolved as `Abs`) or the tagged
+/// `{"abs"|"bps"|"mul": ...}` representation, so existing overrides files
+/// written before `Bps`/`Mul` existed keep parsing unchanged.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StakeOverrideRepr {
+    Legacy(u64),
+    Tagged(TaggedStakeOverride),
+}
+
+impl<'de> serde::Deserialize<'de> for StakeOverride {
+    fn deserialize<D>(des: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match StakeOverrideRepr::deserialize(des)? {
+            StakeOverrideRepr::Legacy(stake) => StakeOverride::Abs(stake),
+            StakeOverrideRepr::Tagged(TaggedStakeOverride::Abs(stake)) => StakeOverride::Abs(stake),
+            StakeOverrideRepr::Tagged(TaggedStakeOverride::Bps(bps)) => StakeOverride::Bps(bps),
+            StakeOverrideRepr::Tagged(TaggedStakeOverride::Mul(mul)) => StakeOverride::Mul(mul),
+        })
+    }
+}
+
+pub fn deserialize_pubkey_map<'de, D, V>(des: D) -> Result<HashMap<Pubkey, V>, D::Error>
 where
     D: Deserializer<'de>,
+    V: serde::Deserialize<'de>,
 {
-    let container: HashMap<String, u64> = serde::Deserialize::deserialize(des)?;
-    let mut container_typed: HashMap<Pubkey, u64> = HashMap::new();
-    for (key, value) in container.iter() {
+    let container: HashMap<String, V> = serde::Deserialize::deserialize(des)?;
+    let mut container_typed: HashMap<Pubkey, V> = HashMap::new();
+    for (key, value) in container.into_iter() {
         let typed_key = Pubkey::try_from(key.as_str())
             .map_err(|_| serde::de::Error::invalid_type(serde::de::Unexpected::Map, &"PubKey"))?;
-        container_typed.insert(typed_key, *value);
+        container_typed.insert(typed_key, value);
     }
     Ok(container_typed)
 }
@@ -49,99 +118,404 @@ impl StakedNodesUpdaterService {
         bank_forks: Arc<RwLock<BankForks>>,
         shared_staked_nodes: Arc<RwLock<StakedNodes>>,
         shared_staked_nodes_overrides: Arc<RwLock<StakedNodesOverrides>>,
+        overrides_path: Option<PathBuf>,
     ) -> Self {
         let thread_hdl = Builder::new()
             .name("sol-sn-updater".to_string())
             .spawn(move || {
                 let mut last_stakes = Instant::now();
+                // Checked on the same cadence as try_refresh_stake_maps, so a
+                // missing/unreadable/malformed path doesn't spin the ~1ms
+                // idle-sleep loop into a log flood.
+                let mut last_overrides_check = Instant::now() - IP_TO_STAKE_REFRESH_DURATION;
+                let mut last_overrides_mtime = None;
+                let mut last_overrides_error = None;
+                let mut previous_ip_to_stake: HashMap<IpAddr, u64> = HashMap::new();
+                let mut previous_id_to_stake: HashMap<Pubkey, u64> = HashMap::new();
                 while !exit.load(Ordering::Relaxed) {
+                    if let Some(path) = overrides_path.as_deref() {
+                        if last_overrides_check.elapsed() > IP_TO_STAKE_REFRESH_DURATION {
+                            Self::maybe_reload_overrides_file(
+                                path,
+                                &mut last_overrides_mtime,
+                                &mut last_overrides_error,
+                                &shared_staked_nodes_overrides,
+                            );
+                            last_overrides_check = Instant::now();
+                        }
+                    }
                     let overrides = shared_staked_nodes_overrides.read().unwrap();
-                    let mut new_ip_to_stake = HashMap::new();
-                    let mut new_id_to_stake = HashMap::new();
-                    let mut total_stake = 0;
-                    if Self::try_refresh_stake_maps(
-                        &mut last_stakes,
-                        &mut new_ip_to_stake,
-                        &mut new_id_to_stake,
-                        &mut total_stake,
-                        &bank_forks,
-                        &cluster_info,
-                        &overrides,
-                    ) {
-                        let mut shared = shared_staked_nodes.write().unwrap();
-                        shared.total_stake = total_stake;
-                        shared.ip_stake_map = new_ip_to_stake;
-                        shared.pubkey_stake_map = new_id_to_stake;
+                    if let Some((new_ip_to_stake, new_id_to_stake, total_stake)) =
+                        Self::try_refresh_stake_maps(
+                            &mut last_stakes,
+                            &bank_forks,
+                            &cluster_info,
+                            &overrides,
+                        )
+                    {
+                        Self::apply_stake_maps_diff(
+                            &shared_staked_nodes,
+                            &previous_ip_to_stake,
+                            &new_ip_to_stake,
+                            &previous_id_to_stake,
+                            &new_id_to_stake,
+                            total_stake,
+                        );
+                        previous_ip_to_stake = new_ip_to_stake;
+                        previous_id_to_stake = new_id_to_stake;
                     }
                 }
             })
             .unwrap();
 
-        Self { thread_hdl }
+        Self {
+            thread_hdl: Some(thread_hdl),
+        }
+    }
+
+    /// Installs a fixed, hand-built stake table once and returns immediately,
+    /// without spinning the refresh thread against `bank_forks`/`cluster_info`.
+    /// Unlike `new`, override pubkeys do not need to already appear in
+    /// `tvu_peers()`, since there is no gossip state to validate them against.
+    /// Intended for integration tests and simulators that want to exercise
+    /// QUIC stake-weighted prioritization with a hand-built stake table.
+    ///
+    /// There is no IP information to build `ip_stake_map` from in this mode,
+    /// so it is left empty; only `pubkey_stake_map` (the map QUIC's
+    /// stake-weighted prioritization keys on) is populated from `staked_map`.
+    pub fn new_static(
+        shared_staked_nodes: Arc<RwLock<StakedNodes>>,
+        staked_map: HashMap<Pubkey, u64>,
+    ) -> Self {
+        let total_stake = staked_map.values().sum();
+        let mut shared = shared_staked_nodes.write().unwrap();
+        shared.total_stake = total_stake;
+        shared.ip_stake_map = HashMap::new();
+        shared.pubkey_stake_map = staked_map;
+        drop(shared);
+
+        Self { thread_hdl: None }
     }
 
+    /// Re-reads `path` and swaps it into `shared_staked_nodes_overrides` if its
+    /// mtime has changed since the last check. On a parse error the previous
+    /// overrides are left in place and the failure is logged, so a bad edit to
+    /// the file never clears existing overrides.
+    ///
+    /// Called on the `IP_TO_STAKE_REFRESH_DURATION` cadence rather than every
+    /// loop iteration, and `last_error` dedupes repeated identical stat/parse
+    /// failures so a single bad path logs once instead of on every check.
+    fn maybe_reload_overrides_file(
+        path: &Path,
+        last_mtime: &mut Option<SystemTime>,
+        last_error: &mut Option<String>,
+        shared_staked_nodes_overrides: &RwLock<StakedNodesOverrides>,
+    ) {
+        let mtime = match fs::metadata(path).and_then(|meta| meta.modified()) {
+            Ok(mtime) => mtime,
+            Err(err) => {
+                let message = format!("failed to stat staked nodes overrides file {:?}: {}", path, err);
+                if last_error.as_ref() != Some(&message) {
+                    error!("{message}");
+                    *last_error = Some(message);
+                }
+                return;
+            }
+        };
+        if *last_mtime == Some(mtime) {
+            return;
+        }
+        *last_mtime = Some(mtime);
+
+        let new_overrides = fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| {
+                serde_json::from_str::<StakedNodesOverrides>(&contents).map_err(|err| err.to_string())
+            });
+        match new_overrides {
+            Ok(new_overrides) => {
+                *shared_staked_nodes_overrides.write().unwrap() = new_overrides;
+                *last_error = None;
+            }
+            Err(err) => {
+                let message = format!(
+                    "failed to parse staked nodes overrides file {:?}, keeping previous overrides: {}",
+                    path, err
+                );
+                if last_error.as_ref() != Some(&message) {
+                    error!("{message}");
+                    *last_error = Some(message);
+                }
+            }
+        }
+    }
+
+    /// Snapshots `cluster_info.tvu_peers()` once and builds the next
+    /// ip/pubkey stake maps from that single snapshot, returning them
+    /// alongside the new `total_stake`. Returns `None` before a refresh is
+    /// due.
     fn try_refresh_stake_maps(
         last_stakes: &mut Instant,
-        ip_to_stake: &mut HashMap<IpAddr, u64>,
-        id_to_stake: &mut HashMap<Pubkey, u64>,
-        total_stake: &mut u64,
         bank_forks: &RwLock<BankForks>,
         cluster_info: &ClusterInfo,
         overrides: &RwLockReadGuard<StakedNodesOverrides>,
-    ) -> bool {
-        if last_stakes.elapsed() > IP_TO_STAKE_REFRESH_DURATION {
-            let root_bank = bank_forks.read().unwrap().root_bank();
-            let staked_nodes = root_bank.staked_nodes();
-            *total_stake = staked_nodes
-                .iter()
-                .map(|(_pubkey, stake)| stake)
-                .sum::<u64>();
-            *id_to_stake = cluster_info
-                .tvu_peers()
-                .into_iter()
-                .filter_map(|node| {
-                    let stake = staked_nodes.get(&node.id)?;
-                    Some((node.id, *stake))
-                })
-                .collect();
-            *ip_to_stake = cluster_info
-                .tvu_peers()
-                .into_iter()
-                .filter_map(|node| {
-                    let stake = staked_nodes.get(&node.id)?;
-                    Some((node.tvu.ip(), *stake))
-                })
-                .collect();
-            for (id_override, stake_override) in overrides.staked_map_id.iter() {
-                if let Some(ip_override) = cluster_info.tvu_peers().into_iter().find_map(|node| {
-                    if node.id == *id_override {
-                        return Some(node.tvu.ip());
-                    }
-                    None
-                }) {
-                    if let Some(previous_stake) = id_to_stake.get(id_override) {
-                        *total_stake -= previous_stake;
-                    }
-                    *total_stake += stake_override;
-                    id_to_stake.insert(*id_override, *stake_override);
-                    ip_to_stake.insert(ip_override, *stake_override);
-                } else {
-                    error!(
-                        "staked nodes overrides configuration for id {} with stake {} does not match existing IP. Skipping",
-                        id_override, stake_override
-                    );
+    ) -> Option<(HashMap<IpAddr, u64>, HashMap<Pubkey, u64>, u64)> {
+        if last_stakes.elapsed() <= IP_TO_STAKE_REFRESH_DURATION {
+            sleep(Duration::from_millis(1));
+            return None;
+        }
+
+        let root_bank = bank_forks.read().unwrap().root_bank();
+        let staked_nodes = root_bank.staked_nodes();
+        let peers = cluster_info.tvu_peers();
+
+        let mut total_stake = staked_nodes
+            .iter()
+            .map(|(_pubkey, stake)| stake)
+            .sum::<u64>();
+        let mut id_to_stake = HashMap::with_capacity(peers.len());
+        let mut ip_to_stake = HashMap::with_capacity(peers.len());
+        let mut ip_by_id = HashMap::with_capacity(peers.len());
+        for node in &peers {
+            ip_by_id.insert(node.id, node.tvu.ip());
+            if let Some(stake) = staked_nodes.get(&node.id) {
+                id_to_stake.insert(node.id, *stake);
+                ip_to_stake.insert(node.tvu.ip(), *stake);
+            }
+        }
+
+        // Resolve every override against a single base_total_stake snapshot
+        // taken before any override is applied, so Bps/Mul overrides don't
+        // depend on the nondeterministic HashMap iteration order.
+        let base_total_stake = total_stake;
+        for (id_override, stake_override) in overrides.staked_map_id.iter() {
+            if let Some(ip_override) = ip_by_id.get(id_override) {
+                let existing_stake = id_to_stake.get(id_override).copied().unwrap_or(0);
+                let resolved_stake = stake_override.resolve(existing_stake, base_total_stake);
+                if let Some(previous_stake) = id_to_stake.get(id_override) {
+                    total_stake -= previous_stake;
                 }
+                total_stake += resolved_stake;
+                id_to_stake.insert(*id_override, resolved_stake);
+                ip_to_stake.insert(*ip_override, resolved_stake);
+            } else {
+                error!(
+                    "staked nodes overrides configuration for id {} with override {:?} does not match existing IP. Skipping",
+                    id_override, stake_override
+                );
             }
+        }
 
-            *last_stakes = Instant::now();
-            true
-        } else {
-            sleep(Duration::from_millis(1));
-            false
+        *last_stakes = Instant::now();
+        Some((ip_to_stake, id_to_stake, total_stake))
+    }
+
+    /// Applies only the added/changed/removed entries between the previous
+    /// and new stake maps to `shared_staked_nodes`, so the write lock that
+    /// blocks QUIC stake lookups is held for the diff instead of a full
+    /// rebuild. Skips taking the write lock when nothing changed.
+    fn apply_stake_maps_diff(
+        shared_staked_nodes: &RwLock<StakedNodes>,
+        previous_ip_to_stake: &HashMap<IpAddr, u64>,
+        new_ip_to_stake: &HashMap<IpAddr, u64>,
+        previous_id_to_stake: &HashMap<Pubkey, u64>,
+        new_id_to_stake: &HashMap<Pubkey, u64>,
+        total_stake: u64,
+    ) {
+        // total_stake is summed over every staked node in the bank, not just
+        // tvu_peers(), so it can change even when neither peer map does; check
+        // it under a read lock before deciding whether to skip entirely.
+        let total_stake_changed = shared_staked_nodes.read().unwrap().total_stake != total_stake;
+        if !total_stake_changed
+            && previous_ip_to_stake == new_ip_to_stake
+            && previous_id_to_stake == new_id_to_stake
+        {
+            return;
+        }
+
+        let mut shared = shared_staked_nodes.write().unwrap();
+        shared.total_stake = total_stake;
+
+        for ip in previous_ip_to_stake.keys() {
+            if !new_ip_to_stake.contains_key(ip) {
+                shared.ip_stake_map.remove(ip);
+            }
+        }
+        for (ip, stake) in new_ip_to_stake {
+            if previous_ip_to_stake.get(ip) != Some(stake) {
+                shared.ip_stake_map.insert(*ip, *stake);
+            }
+        }
+
+        for id in previous_id_to_stake.keys() {
+            if !new_id_to_stake.contains_key(id) {
+                shared.pubkey_stake_map.remove(id);
+            }
         }
+        for (id, stake) in new_id_to_stake {
+            if previous_id_to_stake.get(id) != Some(stake) {
+                shared.pubkey_stake_map.insert(*id, *stake);
+            }
+        }
+    }
+
+    /// Inserts or replaces a single stake override for `pubkey`, taking effect
+    /// on the next `try_refresh_stake_maps` pass. Rejects pubkeys that are not
+    /// currently present among `cluster_info.tvu_peers()`, surfacing the same
+    /// diagnostic that `try_refresh_stake_maps` would otherwise only log.
+    pub fn set_staked_override(
+        shared_staked_nodes_overrides: &RwLock<StakedNodesOverrides>,
+        cluster_info: &ClusterInfo,
+        pubkey: Pubkey,
+        stake_override: StakeOverride,
+    ) -> Result<(), String> {
+        if !cluster_info.tvu_peers().iter().any(|node| node.id == pubkey) {
+            return Err(format!(
+                "staked nodes overrides configuration for id {} with override {:?} does not match existing IP. Skipping",
+                pubkey, stake_override
+            ));
+        }
+        shared_staked_nodes_overrides
+            .write()
+            .unwrap()
+            .staked_map_id
+            .insert(pubkey, stake_override);
+        Ok(())
+    }
+
+    /// Removes a single stake override for `pubkey`, if one is present,
+    /// returning its previous value.
+    pub fn remove_staked_override(
+        shared_staked_nodes_overrides: &RwLock<StakedNodesOverrides>,
+        pubkey: &Pubkey,
+    ) -> Option<StakeOverride> {
+        shared_staked_nodes_overrides
+            .write()
+            .unwrap()
+            .staked_map_id
+            .remove(pubkey)
     }
 
     pub fn join(self) -> thread::Result<()> {
-        self.thread_hdl.join()
+        match self.thread_hdl {
+            Some(thread_hdl) => thread_hdl.join(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_static_populates_pubkey_stake_map_only() {
+        let shared_staked_nodes = Arc::new(RwLock::new(StakedNodes::default()));
+        let pubkey_a = Pubkey::new_unique();
+        let pubkey_b = Pubkey::new_unique();
+        let staked_map = HashMap::from([(pubkey_a, 100), (pubkey_b, 300)]);
+
+        let service = StakedNodesUpdaterService::new_static(shared_staked_nodes.clone(), staked_map);
+
+        let shared = shared_staked_nodes.read().unwrap();
+        assert_eq!(shared.total_stake, 400);
+        assert_eq!(shared.pubkey_stake_map.get(&pubkey_a), Some(&100));
+        assert_eq!(shared.pubkey_stake_map.get(&pubkey_b), Some(&300));
+        assert!(shared.ip_stake_map.is_empty());
+        drop(shared);
+
+        service.join().unwrap();
+    }
+
+    #[test]
+    fn test_stake_override_resolve() {
+        assert_eq!(StakeOverride::Abs(42).resolve(0, 1_000), 42);
+        assert_eq!(StakeOverride::Bps(500).resolve(0, 1_000), 50);
+        assert_eq!(StakeOverride::Mul(2.0).resolve(25, 1_000), 50);
+
+        // A large validator's stake (above 2^53 lamports) must round-trip
+        // exactly through Mul, since the multiply happens in u128 space.
+        let large_stake = (1u64 << 53) + 7;
+        assert_eq!(
+            StakeOverride::Mul(1.0).resolve(large_stake, 0),
+            large_stake
+        );
+    }
+
+    #[test]
+    fn test_stake_override_deserialize_accepts_legacy_bare_u64() {
+        let legacy: StakeOverride = serde_json::from_str("42").unwrap();
+        assert!(matches!(legacy, StakeOverride::Abs(42)));
+
+        let tagged: StakeOverride = serde_json::from_str(r#"{"bps": 500}"#).unwrap();
+        assert!(matches!(tagged, StakeOverride::Bps(500)));
+    }
+
+    #[test]
+    fn test_apply_stake_maps_diff_applies_add_change_and_remove() {
+        let shared_staked_nodes = Arc::new(RwLock::new(StakedNodes::default()));
+        let kept_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let removed_ip: IpAddr = "127.0.0.2".parse().unwrap();
+        let added_ip: IpAddr = "127.0.0.3".parse().unwrap();
+        let kept_id = Pubkey::new_unique();
+        let removed_id = Pubkey::new_unique();
+        let added_id = Pubkey::new_unique();
+
+        let previous_ip_to_stake = HashMap::from([(kept_ip, 100), (removed_ip, 200)]);
+        let previous_id_to_stake = HashMap::from([(kept_id, 100), (removed_id, 200)]);
+        StakedNodesUpdaterService::apply_stake_maps_diff(
+            &shared_staked_nodes,
+            &HashMap::new(),
+            &previous_ip_to_stake,
+            &HashMap::new(),
+            &previous_id_to_stake,
+            300,
+        );
+
+        // kept_ip/kept_id change stake, removed_ip/removed_id disappear, added_ip/added_id appear.
+        let new_ip_to_stake = HashMap::from([(kept_ip, 150), (added_ip, 50)]);
+        let new_id_to_stake = HashMap::from([(kept_id, 150), (added_id, 50)]);
+        StakedNodesUpdaterService::apply_stake_maps_diff(
+            &shared_staked_nodes,
+            &previous_ip_to_stake,
+            &new_ip_to_stake,
+            &previous_id_to_stake,
+            &new_id_to_stake,
+            200,
+        );
+
+        let shared = shared_staked_nodes.read().unwrap();
+        assert_eq!(shared.total_stake, 200);
+        assert_eq!(shared.ip_stake_map, new_ip_to_stake);
+        assert_eq!(shared.pubkey_stake_map, new_id_to_stake);
+    }
+
+    #[test]
+    fn test_apply_stake_maps_diff_updates_total_stake_when_peer_maps_are_unchanged() {
+        let shared_staked_nodes = Arc::new(RwLock::new(StakedNodes::default()));
+        let ip_to_stake = HashMap::from([("127.0.0.1".parse::<IpAddr>().unwrap(), 100)]);
+        let id_to_stake = HashMap::from([(Pubkey::new_unique(), 100)]);
+
+        StakedNodesUpdaterService::apply_stake_maps_diff(
+            &shared_staked_nodes,
+            &ip_to_stake,
+            &ip_to_stake,
+            &id_to_stake,
+            &id_to_stake,
+            100,
+        );
+        assert_eq!(shared_staked_nodes.read().unwrap().total_stake, 100);
+
+        // Simulate stake changing on a validator that is not a tvu_peer: the
+        // peer maps stay identical but total_stake still moves.
+        StakedNodesUpdaterService::apply_stake_maps_diff(
+            &shared_staked_nodes,
+            &ip_to_stake,
+            &ip_to_stake,
+            &id_to_stake,
+            &id_to_stake,
+            500,
+        );
+        assert_eq!(shared_staked_nodes.read().unwrap().total_stake, 500);
     }
 }